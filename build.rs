@@ -1,5 +1,7 @@
 use std::io::Result;
 fn main() -> Result<()> {
-    prost_build::compile_protos(&["src/proto/context.proto", "src/proto/toggles.proto"], &["src/"])?;
+    prost_build::Config::new()
+        .type_attribute(".", "#[derive(serde::Serialize, serde::Deserialize)]")
+        .compile_protos(&["src/proto/context.proto", "src/proto/toggles.proto"], &["src/"])?;
     Ok(())
-}
\ No newline at end of file
+}
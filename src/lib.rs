@@ -1,3 +1,4 @@
+use std::borrow::Cow;
 use std::collections::HashMap;
 use prost::Message;
 use serde::{Deserialize, Serialize, de::DeserializeOwned};
@@ -43,9 +44,11 @@ impl Into<Context> for OtherContext {
 enum Error {
     Utf8Error,
     NullError,
+    NotFound,
     InvalidJson(String),
     PartialUpdate(Vec<EvalWarning>),
     InvalidProto(String),
+    UnsupportedWireFormat(u8),
 }
 
 impl Display for Error {
@@ -53,6 +56,7 @@ impl Display for Error {
         match self {
             Error::Utf8Error => write!(f, "Detected a non UTF-8 string in the input, this is a serious issue and you should report this as a bug."),
             Error::NullError => write!(f, "Null error detected, this is a serious issue and you should report this as a bug."),
+            Error::NotFound => write!(f, "The requested toggle could not be found in the current engine state."),
             Error::InvalidJson(message) => write!(f, "Failed to parse JSON: {}", message),
             Error::PartialUpdate(messages) => write!(
                 f,
@@ -60,6 +64,47 @@ impl Display for Error {
                 messages
             ),
             Error::InvalidProto(message) => write!(f, "Invalid Proto Buf input detected: {}", message),
+            Error::UnsupportedWireFormat(format) => write!(
+                f,
+                "Unsupported wire format identifier {}, expected 0 (Protobuf) or 1 (CBOR).",
+                format
+            ),
+        }
+    }
+}
+
+impl Error {
+    /// A stable, language-agnostic identifier for the error variant, meant
+    /// for callers across the FFI boundary to branch on without having to
+    /// string-match `Display` output. Keep these names stable; Go callers
+    /// key off them.
+    fn class(&self) -> &'static str {
+        match self {
+            Error::Utf8Error => "Utf8",
+            Error::NullError => "NullPointer",
+            Error::NotFound => "NotFound",
+            Error::InvalidJson(_) => "InvalidJson",
+            Error::PartialUpdate(_) => "PartialUpdate",
+            Error::InvalidProto(_) => "InvalidProto",
+            Error::UnsupportedWireFormat(_) => "UnsupportedWireFormat",
+        }
+    }
+
+    /// Same identifiers as [`Error::class`], as a static NUL-terminated C
+    /// string. `resolve`/`resolve_all` hand this straight across the FFI
+    /// boundary through an out-parameter instead of wrapping it in the JSON
+    /// `Response` envelope `take_state` uses, so callers can distinguish a
+    /// genuinely absent toggle from a deserialization bug without parsing
+    /// anything.
+    fn class_cstr(&self) -> &'static CStr {
+        match self {
+            Error::Utf8Error => c"Utf8",
+            Error::NullError => c"NullPointer",
+            Error::NotFound => c"NotFound",
+            Error::InvalidJson(_) => c"InvalidJson",
+            Error::PartialUpdate(_) => c"PartialUpdate",
+            Error::InvalidProto(_) => c"InvalidProto",
+            Error::UnsupportedWireFormat(_) => c"UnsupportedWireFormat",
         }
     }
 }
@@ -89,6 +134,11 @@ struct Response<T> {
     status_code: ResponseCode,
     value: Option<T>,
     error_message: Option<String>,
+    /// Stable, machine-readable counterpart to `error_message` (see
+    /// `Error::class`), e.g. `"Utf8"`, `"NullPointer"`, `"InvalidJson"`,
+    /// `"InvalidProto"`, `"PartialUpdate"`, `"NotFound"`. `None` only when
+    /// the call succeeded.
+    error_class: Option<&'static str>,
 }
 
 impl<T> From<Result<Option<T>, Error>> for Response<T> {
@@ -98,21 +148,66 @@ impl<T> From<Result<Option<T>, Error>> for Response<T> {
                 status_code: ResponseCode::Ok,
                 value: Some(enabled),
                 error_message: None,
+                error_class: None,
             },
             Ok(None) => Response {
                 status_code: ResponseCode::NotFound,
                 value: None,
                 error_message: None,
+                error_class: Some("NotFound"),
             },
             Err(e) => Response {
                 status_code: ResponseCode::Error,
                 value: None,
                 error_message: Some(e.to_string()),
+                error_class: Some(e.class()),
             },
         }
     }
 }
 
+#[cfg(test)]
+mod error_class_tests {
+    use super::*;
+
+    #[test]
+    fn class_strings_are_stable_per_variant() {
+        assert_eq!(Error::Utf8Error.class(), "Utf8");
+        assert_eq!(Error::NullError.class(), "NullPointer");
+        assert_eq!(Error::NotFound.class(), "NotFound");
+        assert_eq!(Error::InvalidJson("bad json".into()).class(), "InvalidJson");
+        assert_eq!(Error::PartialUpdate(vec![]).class(), "PartialUpdate");
+        assert_eq!(Error::InvalidProto("bad proto".into()).class(), "InvalidProto");
+        assert_eq!(Error::UnsupportedWireFormat(9).class(), "UnsupportedWireFormat");
+    }
+
+    #[test]
+    fn ok_some_has_no_error_class() {
+        let response: Response<()> = Result::<Option<()>, Error>::Ok(Some(())).into();
+        assert!(response.status_code == ResponseCode::Ok);
+        assert!(response.value.is_some());
+        assert_eq!(response.error_class, None);
+        assert_eq!(response.error_message, None);
+    }
+
+    #[test]
+    fn ok_none_maps_to_not_found() {
+        let response: Response<()> = Result::<Option<()>, Error>::Ok(None).into();
+        assert!(response.status_code == ResponseCode::NotFound);
+        assert!(response.value.is_none());
+        assert_eq!(response.error_class, Some("NotFound"));
+    }
+
+    #[test]
+    fn err_carries_its_error_class() {
+        let response: Response<()> =
+            Result::<Option<()>, Error>::Err(Error::InvalidProto("bad proto".into())).into();
+        assert!(response.status_code == ResponseCode::Error);
+        assert!(response.value.is_none());
+        assert_eq!(response.error_class, Some("InvalidProto"));
+    }
+}
+
 #[derive(Clone, Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ResolvedToggleState {
@@ -143,19 +238,20 @@ pub unsafe extern "C" fn free_engine(engine_ptr: *mut c_void) {
 pub unsafe extern "C" fn take_state(
     engine_ptr: *mut c_void,
     json_ptr: *const c_char,
+    lossy_decoding: bool,
 ) -> *const c_char {
-    let result  = {
-        let guard = get_engine(engine_ptr).unwrap();
+    let result = (|| {
+        let guard = get_engine(engine_ptr)?;
         let mut engine = recover_lock(&guard);
 
-        let toggles: UpdateMessage = get_json(json_ptr).unwrap();
+        let toggles: UpdateMessage = get_json(json_ptr, lossy_decoding)?;
 
         if let Some(warnings) = engine.take_state(toggles) {
             Err(Error::PartialUpdate(warnings))
         } else {
             Ok(Some(()))
         }
-    };
+    })();
 
     result_to_json_ptr(result)
 }
@@ -166,6 +262,8 @@ pub unsafe extern "C" fn resolve_all(
     context_data: *const u8,
     include_all: *const bool,
     context_len: usize,
+    format: u8,
+    error_class_out: *mut *const c_char,
     out_len: *mut usize,
 ) -> *const u8 {
     let result: Result<Vec<u8>, Error> = (|| {
@@ -178,16 +276,15 @@ pub unsafe extern "C" fn resolve_all(
 
         let context: Context = context_proto.into();
         let resolved = engine.resolve_all(&context, &None)
-            .ok_or(Error::NullError)?;
+            .ok_or(Error::NotFound)?;
 
         let list: EvaluatedToggleList = into_list(resolved, *include_all);
 
-        // Serialize to Protobuf bytes
-        let mut buf = Vec::new();
-        list.0.encode(&mut buf).map_err(|_| Error::InvalidJson("Error".into()))?;
-        Ok(buf)
+        encode_with_format(&list.0, format)
     })();
 
+    unsafe { write_error_class_out(error_class_out, result.as_ref().err()) }
+
     match result {
         Ok(bytes) => {
             unsafe {
@@ -207,8 +304,11 @@ pub unsafe extern "C" fn resolve_all(
 pub unsafe extern "C" fn resolve(
     engine_ptr: *mut c_void,
     toggle_name_ptr: *const c_char,
+    lossy_decoding: bool,
     context_data: *const u8,
     context_len: usize,
+    format: u8,
+    error_class_out: *mut *const c_char,
     out_len: *mut usize,
 ) -> *const u8 {
     let result: Result<Vec<u8>, Error> = (|| {
@@ -216,15 +316,15 @@ pub unsafe extern "C" fn resolve(
         let engine = recover_lock(&guard);
 
         // 1. Handle Inputs
-        let toggle_name = get_str(toggle_name_ptr)?;
+        let toggle_name = get_str(toggle_name_ptr, lossy_decoding)?;
         let input_slice = std::slice::from_raw_parts(context_data, context_len);
         let context_proto = OtherContext::decode(input_slice)
             .map_err(|_| Error::InvalidProto("Invalid Context".into()))?;
         let context: Context = context_proto.into();
 
         // 2. Resolve Logic
-        let resolved = engine.resolve(toggle_name, &context, &None)
-            .ok_or(Error::NullError)?;
+        let resolved = engine.resolve(&toggle_name, &context, &None)
+            .ok_or(Error::NotFound)?;
 
         let evaluated = EvaluatedToggle {
             name: toggle_name.to_string(),
@@ -243,11 +343,11 @@ pub unsafe extern "C" fn resolve(
         };
 
         // 4. Serialize
-        let mut buf = Vec::new();
-        evaluated.encode(&mut buf).map_err(|_| Error::InvalidJson("Error".into()))?;
-        Ok(buf)
+        encode_with_format(&evaluated, format)
     })();
 
+    write_error_class_out(error_class_out, result.as_ref().err());
+
     // 5. Return binary pointer and length to Go
     match result {
         Ok(bytes) => {
@@ -263,6 +363,24 @@ pub unsafe extern "C" fn resolve(
     }
 }
 
+/// Writes the stable `error_class` identifier for `error` into `*out`, or a
+/// null pointer on success. `resolve`/`resolve_all` use this to surface
+/// `Error::class` across the FFI boundary without the `Response` JSON
+/// envelope `take_state` uses. `out` may itself be null if the caller
+/// doesn't want the class; callers never need to free the pointer it
+/// receives, it always points at static storage.
+unsafe fn write_error_class_out(out: *mut *const c_char, error: Option<&Error>) {
+    if out.is_null() {
+        return;
+    }
+    unsafe {
+        *out = match error {
+            Some(e) => e.class_cstr().as_ptr(),
+            None => std::ptr::null(),
+        };
+    }
+}
+
 unsafe fn get_engine(engine_ptr: *mut c_void) -> Result<ManagedEngine, Error> {
     if engine_ptr.is_null() {
         return Err(Error::NullError);
@@ -279,19 +397,227 @@ fn recover_lock<T>(lock: &Mutex<T>) -> MutexGuard<'_, T> {
     lock.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
 }
 
-unsafe fn get_json<T: DeserializeOwned>(json_ptr: *const c_char) -> Result<T, Error> {
+unsafe fn get_json<T: DeserializeOwned>(json_ptr: *const c_char, lossy_decoding: bool) -> Result<T, Error> {
     unsafe {
-        let json_str = get_str(json_ptr)?;
-        serde_json::from_str(json_str).map_err(Error::from)
+        let json_str = get_str(json_ptr, lossy_decoding)?;
+        if lossy_decoding {
+            let sanitized = desurrogate_lossy(&json_str);
+            serde_json::from_str(&sanitized).map_err(Error::from)
+        } else {
+            serde_json::from_str(&json_str).map_err(Error::from)
+        }
     }
 }
 
 
-unsafe fn get_str<'a>(ptr: *const c_char) -> Result<&'a str, Error> {
+unsafe fn get_str<'a>(ptr: *const c_char, lossy_decoding: bool) -> Result<Cow<'a, str>, Error> {
     if ptr.is_null() {
         Err(Error::NullError)
+    } else if lossy_decoding {
+        unsafe { Ok(CStr::from_ptr(ptr).to_string_lossy()) }
     } else {
-        unsafe { CStr::from_ptr(ptr).to_str().map_err(Error::from) }
+        unsafe { CStr::from_ptr(ptr).to_str().map(Cow::Borrowed).map_err(Error::from) }
+    }
+}
+
+/// Rewrites unpaired UTF-16 surrogate escapes (`\uD800`-`\uDFFF` not forming a
+/// valid high/low surrogate pair) in a raw JSON document to a literal U+FFFD
+/// replacement character, so `serde_json` never has to reject an
+/// otherwise well-formed payload just because one property string contains a
+/// stray surrogate. The `UpdateMessage` JSON read by `take_state` carries
+/// arbitrary user-supplied strings that we don't control the source of, so we
+/// sanitize at the document level rather than requiring every upstream string
+/// field to opt in individually. Only JSON input goes through here: the
+/// Protobuf `Context` decoded by `resolve`/`resolve_all` is not sanitized.
+///
+/// Escapes are walked pairwise (a `\` is always consumed together with
+/// whatever follows it) so an escaped backslash immediately followed by
+/// literal text that merely looks like `uXXXX` — e.g. the JSON string
+/// `"\\uD800"`, six literal characters with no surrogate involved — is never
+/// mistaken for a fresh `\uXXXX` escape. Only called when the caller opted
+/// into lossy decoding; left untouched otherwise.
+fn desurrogate_lossy(input: &str) -> Cow<'_, str> {
+    fn parse_hex4(bytes: &[u8]) -> Option<u32> {
+        std::str::from_utf8(bytes).ok().and_then(|s| u32::from_str_radix(s, 16).ok())
+    }
+
+    let bytes = input.as_bytes();
+    let mut out: Option<String> = None;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 1 < bytes.len() {
+            if bytes[i + 1] == b'u' && i + 6 <= bytes.len() {
+                let Some(high) = parse_hex4(&bytes[i + 2..i + 6]) else {
+                    // Malformed hex digits: not our concern, copy the `\u`
+                    // marker through and let serde_json reject it downstream.
+                    if let Some(out) = out.as_mut() {
+                        out.push_str(&input[i..i + 2]);
+                    }
+                    i += 2;
+                    continue;
+                };
+
+                let is_high_surrogate = (0xD800..=0xDBFF).contains(&high);
+                let is_low_surrogate = (0xDC00..=0xDFFF).contains(&high);
+
+                if is_high_surrogate
+                    && i + 12 <= bytes.len()
+                    && bytes[i + 6] == b'\\'
+                    && bytes[i + 7] == b'u'
+                    && parse_hex4(&bytes[i + 8..i + 12]).is_some_and(|low| (0xDC00..=0xDFFF).contains(&low))
+                {
+                    // Valid surrogate pair, copy both escapes through untouched.
+                    if let Some(out) = out.as_mut() {
+                        out.push_str(&input[i..i + 12]);
+                    }
+                    i += 12;
+                } else if is_high_surrogate || is_low_surrogate {
+                    // Lone surrogate half: substitute the replacement character.
+                    out.get_or_insert_with(|| input[..i].to_string()).push('\u{FFFD}');
+                    i += 6;
+                } else {
+                    if let Some(out) = out.as_mut() {
+                        out.push_str(&input[i..i + 6]);
+                    }
+                    i += 6;
+                }
+            } else {
+                // Any other escape (`\\`, `\"`, `\n`, ...): consume the
+                // backslash together with whatever it escapes as a single
+                // unit, so a literal backslash is never left dangling to be
+                // misread as the start of a fresh `\u` escape on the next
+                // iteration.
+                if let Some(out) = out.as_mut() {
+                    out.push_str(&input[i..i + 2]);
+                }
+                i += 2;
+            }
+        } else {
+            let ch_len = input[i..].chars().next().map_or(1, char::len_utf8);
+            if let Some(out) = out.as_mut() {
+                out.push_str(&input[i..i + ch_len]);
+            }
+            i += ch_len;
+        }
+    }
+
+    match out {
+        Some(sanitized) => Cow::Owned(sanitized),
+        None => Cow::Borrowed(input),
+    }
+}
+
+#[cfg(test)]
+mod desurrogate_lossy_tests {
+    use super::*;
+
+    #[test]
+    fn passes_through_text_with_no_escapes() {
+        let input = r#"{"prop":"plain value"}"#;
+        assert_eq!(desurrogate_lossy(input).as_ref(), input);
+    }
+
+    #[test]
+    fn passes_through_valid_surrogate_pair() {
+        let input = "{\"prop\":\"\\uD83D\\uDE00\"}";
+        assert_eq!(desurrogate_lossy(input).as_ref(), input);
+    }
+
+    #[test]
+    fn replaces_lone_high_surrogate() {
+        let input = r#"{"prop":"\uD800"}"#;
+        assert_eq!(desurrogate_lossy(input).as_ref(), "{\"prop\":\"\u{FFFD}\"}");
+    }
+
+    #[test]
+    fn replaces_lone_low_surrogate() {
+        let input = r#"{"prop":"\uDC00"}"#;
+        assert_eq!(desurrogate_lossy(input).as_ref(), "{\"prop\":\"\u{FFFD}\"}");
+    }
+
+    #[test]
+    fn does_not_corrupt_escaped_backslash_followed_by_literal_u_digits() {
+        // The JSON string value is the six literal characters `\uD800`
+        // (an escaped backslash followed by plain text), not a surrogate.
+        let input = r#"{"prop":"\\uD800"}"#;
+        assert_eq!(desurrogate_lossy(input).as_ref(), input);
+    }
+
+    #[test]
+    fn still_detects_real_surrogate_after_an_escaped_backslash() {
+        let input = r#"{"prop":"\\\uD800"}"#;
+        assert_eq!(desurrogate_lossy(input).as_ref(), "{\"prop\":\"\\\\\u{FFFD}\"}");
+    }
+}
+
+/// Wire format for the binary payloads returned by `resolve`/`resolve_all`.
+/// Evaluation itself is unaffected, only the final `encode` step diverges:
+/// `0` keeps the existing Protobuf encoding, `1` serializes the same
+/// evaluated structs as CBOR for callers that don't want to vendor the
+/// generated prost types.
+fn encode_with_format<T: Message + Serialize>(value: &T, format: u8) -> Result<Vec<u8>, Error> {
+    match format {
+        0 => {
+            let mut buf = Vec::new();
+            value.encode(&mut buf).map_err(|_| Error::InvalidJson("Error".into()))?;
+            Ok(buf)
+        }
+        1 => {
+            let mut buf = Vec::new();
+            ciborium::into_writer(value, &mut buf)
+                .map_err(|e| Error::InvalidJson(format!("Failed to encode CBOR: {e}")))?;
+            Ok(buf)
+        }
+        other => Err(Error::UnsupportedWireFormat(other)),
+    }
+}
+
+#[cfg(test)]
+mod encode_with_format_tests {
+    use super::*;
+
+    fn sample_toggle() -> EvaluatedToggle {
+        EvaluatedToggle {
+            name: "my-toggle".to_string(),
+            enabled: true,
+            impression_data: false,
+            variant: Some(EvaluatedVariant {
+                name: "variant-a".to_string(),
+                enabled: true,
+                feature_enabled: true,
+                old_feature_enabled: true,
+                payload: Some(VariantPayload {
+                    r#type: "string".to_string(),
+                    value: "payload-value".to_string(),
+                }),
+            }),
+        }
+    }
+
+    #[test]
+    fn protobuf_format_round_trips() {
+        let toggle = sample_toggle();
+        let bytes = encode_with_format(&toggle, 0).expect("protobuf encode should succeed");
+        let decoded = EvaluatedToggle::decode(bytes.as_slice()).expect("protobuf decode should succeed");
+        assert_eq!(decoded, toggle);
+    }
+
+    #[test]
+    fn cbor_format_round_trips() {
+        let toggle = sample_toggle();
+        let bytes = encode_with_format(&toggle, 1).expect("cbor encode should succeed");
+        let decoded: EvaluatedToggle =
+            ciborium::from_reader(bytes.as_slice()).expect("cbor decode should succeed");
+        assert_eq!(decoded, toggle);
+    }
+
+    #[test]
+    fn unknown_format_is_rejected() {
+        let toggle = sample_toggle();
+        let err = encode_with_format(&toggle, 42).expect_err("unknown format id should be rejected");
+        assert!(matches!(err, Error::UnsupportedWireFormat(42)));
+        assert_eq!(err.class(), "UnsupportedWireFormat");
     }
 }
 